@@ -0,0 +1,102 @@
+use std::fmt;
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Nonce,
+};
+
+use crate::{error::Error, Codec};
+
+/// The length of the nonce stored in front of every ciphertext
+const NONCE_LEN: usize = 12;
+
+/// AES-256-GCM encryption of the bytes produced by another codec
+///
+/// Every payload is encrypted with a fresh random nonce, which is stored in front of the
+/// ciphertext. Wrap a compressing codec to compress before encrypting, as ciphertext
+/// does not compress.
+#[derive(Clone)]
+pub struct EncryptingCodec<C> {
+    inner: C,
+    cipher: Aes256Gcm,
+}
+
+impl<C> EncryptingCodec<C> {
+    /// Encrypt the output of `inner` with a 256 bit `key`
+    pub fn new(inner: C, key: &[u8; 32]) -> Self {
+        Self {
+            inner,
+            cipher: Aes256Gcm::new(key.into()),
+        }
+    }
+}
+
+impl<C: fmt::Debug> fmt::Debug for EncryptingCodec<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptingCodec")
+            .field("inner", &self.inner)
+            .field("cipher", &"Aes256Gcm")
+            .finish()
+    }
+}
+
+impl<T, C: Codec<T, Vec<u8>, Error = Error>> Codec<T, Vec<u8>> for EncryptingCodec<C> {
+    type Error = Error;
+    fn encode(&self, input: &T) -> Result<Vec<u8>, Self::Error> {
+        let plaintext = self.inner.encode(input)?;
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext.as_ref())
+            .map_err(|e| Error::SourceError(Box::new(e)))?;
+        let mut compact = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        compact.extend_from_slice(&nonce);
+        compact.extend_from_slice(&ciphertext);
+        Ok(compact)
+    }
+
+    fn decode(&self, compact: &Vec<u8>) -> Result<T, Self::Error> {
+        if compact.len() < NONCE_LEN {
+            return Err(Error::SourceError("Encrypted payload is too short".into()));
+        }
+        let (nonce, ciphertext) = compact.split_at(NONCE_LEN);
+        let plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|e| Error::SourceError(Box::new(e)))?;
+        self.inner.decode(&plaintext)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Utf8Codec;
+
+    impl Codec<String, Vec<u8>> for Utf8Codec {
+        type Error = Error;
+        fn encode(&self, input: &String) -> Result<Vec<u8>, Self::Error> {
+            Ok(input.as_bytes().to_vec())
+        }
+
+        fn decode(&self, compact: &Vec<u8>) -> Result<String, Self::Error> {
+            String::from_utf8(compact.clone()).map_err(|e| Error::SourceError(Box::new(e)))
+        }
+    }
+
+    #[test]
+    fn it_round_trips_and_rejects_other_keys() {
+        let codec = EncryptingCodec::new(Utf8Codec, &[7; 32]);
+        let secret = "account 12345".to_string();
+        let compact = codec.encode(&secret).unwrap();
+        assert!(!compact.windows(5).any(|w| w == b"12345"));
+        assert_ne!(compact, codec.encode(&secret).unwrap());
+        assert_eq!(codec.decode(&compact).unwrap(), secret);
+
+        let other = EncryptingCodec::new(Utf8Codec, &[8; 32]);
+        assert!(other.decode(&compact).is_err());
+        assert!(codec.decode(&compact[..4].to_vec()).is_err());
+    }
+}
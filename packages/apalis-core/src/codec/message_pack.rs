@@ -0,0 +1,19 @@
+use crate::{error::Error, Codec};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// MessagePack encoding and decoding
+///
+/// Produces a compact binary representation, useful for large numeric payloads
+#[derive(Debug, Clone, Default)]
+pub struct MsgPackCodec;
+
+impl<T: Serialize + DeserializeOwned> Codec<T, Vec<u8>> for MsgPackCodec {
+    type Error = Error;
+    fn encode(&self, input: &T) -> Result<Vec<u8>, Self::Error> {
+        rmp_serde::to_vec_named(input).map_err(|e| Error::SourceError(Box::new(e)))
+    }
+
+    fn decode(&self, compact: &Vec<u8>) -> Result<T, Self::Error> {
+        rmp_serde::from_slice(compact).map_err(|e| Error::SourceError(Box::new(e)))
+    }
+}
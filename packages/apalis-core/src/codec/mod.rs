@@ -1,3 +1,11 @@
 /// Encoding for tasks using json
 #[cfg(feature = "json")]
 pub mod json;
+
+/// Encoding for tasks using message pack
+#[cfg(feature = "msgpack")]
+pub mod message_pack;
+
+/// Encryption of the output of another codec
+#[cfg(feature = "encrypt")]
+pub mod encrypt;
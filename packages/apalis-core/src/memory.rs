@@ -1,7 +1,11 @@
 use crate::{
+    error::Error,
+    layers::{Ack, AckLayer},
     mq::MessageQueue,
     poller::{controller::Controller, stream::BackendStream},
     request::{Request, RequestStream},
+    storage::{Job, Storage},
+    task::{attempt::Attempt, task_id::TaskId},
     worker::WorkerId,
     Backend, Poller,
 };
@@ -10,28 +14,63 @@ use futures::{
     Stream, StreamExt,
 };
 use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, VecDeque},
+    io,
     pin::Pin,
-    sync::Arc,
-    task::{Context, Poll},
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Context, Poll, Waker},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use tower::{layer::util::Identity, ServiceBuilder};
+use ulid::Ulid;
 
 #[derive(Debug)]
-/// An example of the basics of a backend
+/// An in-memory [Storage] for tests and examples that should not depend on a database
+///
+/// Like the sql storages, a job is locked by the worker that fetches it and is done once acknowledged,
+/// while a rescheduled job is due again after its wait. A locked job is held by its worker,
+/// so [Storage::fetch_by_id] only finds it again once it is rescheduled or updated.
+/// Clones share the same jobs, which are lost once the last clone is dropped.
+///
+/// Scheduled jobs are picked up when they are due with the `sleep` feature,
+/// otherwise the next time a job is pushed or rescheduled.
 pub struct MemoryStorage<T> {
     /// Required for [Poller] to control polling.
     controller: Controller,
     /// This would be the backend you are targeting, eg a connection poll
-    inner: MemoryWrapper<T>,
+    inner: Arc<Mutex<MemoryQueue<T>>>,
 }
 impl<T> MemoryStorage<T> {
     /// Create a new in-memory storage
     pub fn new() -> Self {
         Self {
             controller: Controller::new(),
-            inner: MemoryWrapper::new(),
+            inner: Arc::new(Mutex::new(MemoryQueue::default())),
         }
     }
+
+    fn queue(&self) -> MutexGuard<'_, MemoryQueue<T>> {
+        // A panic while holding the lock cannot leave a job half updated
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn insert(&self, job: T, run_at: i64) -> TaskId {
+        let id = TaskId::new();
+        let mut queue = self.queue();
+        queue.jobs.insert(
+            id.inner(),
+            Entry {
+                id: id.clone(),
+                job: Some(job),
+                attempts: 0,
+                run_at,
+                lock_by: None,
+                done: false,
+            },
+        );
+        queue.enqueue(id.inner(), run_at);
+        id
+    }
 }
 
 impl<T> Default for MemoryStorage<T> {
@@ -49,13 +88,139 @@ impl<T> Clone for MemoryStorage<T> {
     }
 }
 
+#[derive(Debug)]
+struct MemoryQueue<T> {
+    jobs: HashMap<Ulid, Entry<T>>,
+    /// Jobs that are due, in the order they became due
+    ready: VecDeque<Ulid>,
+    /// Jobs that are not due yet, earliest first
+    scheduled: BinaryHeap<Reverse<(i64, Ulid)>>,
+    /// Streams waiting for a job to become due
+    wakers: Vec<Waker>,
+}
+
+#[derive(Debug)]
+struct Entry<T> {
+    id: TaskId,
+    /// Moved out while the job is locked and put back once it is rescheduled or updated
+    job: Option<T>,
+    attempts: usize,
+    run_at: i64,
+    lock_by: Option<WorkerId>,
+    done: bool,
+}
+
+impl<T> Entry<T> {
+    fn is_pending(&self) -> bool {
+        self.lock_by.is_none() && !self.done
+    }
+}
+
+impl<T> Default for MemoryQueue<T> {
+    fn default() -> Self {
+        Self {
+            jobs: HashMap::new(),
+            ready: VecDeque::new(),
+            scheduled: BinaryHeap::new(),
+            wakers: Vec::new(),
+        }
+    }
+}
+
+impl<T> MemoryQueue<T> {
+    /// Queue a pending job to be due at `run_at` and wake the waiting streams
+    fn enqueue(&mut self, id: Ulid, run_at: i64) {
+        if run_at <= now() {
+            self.ready.push_back(id);
+        } else {
+            self.scheduled.push(Reverse((run_at, id)));
+        }
+        for waker in self.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// Take the next due pending job out of the queue
+    ///
+    /// Entries left behind by a job that was locked, rescheduled or removed since it was queued are skipped.
+    fn next_due(&mut self) -> Option<Ulid> {
+        let now = now();
+        while let Some(Reverse((run_at, id))) = self.scheduled.peek().copied() {
+            if run_at > now {
+                break;
+            }
+            self.scheduled.pop();
+            if self.jobs.get(&id).map(|e| e.run_at) == Some(run_at) {
+                self.ready.push_back(id);
+            }
+        }
+        while let Some(id) = self.ready.pop_front() {
+            match self.jobs.get(&id) {
+                Some(entry) if entry.is_pending() && entry.job.is_some() && entry.run_at <= now => {
+                    return Some(id)
+                }
+                _ => continue,
+            }
+        }
+        None
+    }
+
+    /// Lock the next due job for `worker`
+    fn lock_next(&mut self, worker: &WorkerId) -> Option<Request<T>> {
+        let id = self.next_due()?;
+        let entry = self.jobs.get_mut(&id)?;
+        let mut req = Request::new(entry.job.take()?);
+        req.insert(entry.id.clone());
+        req.insert(Attempt::new_with_value(entry.attempts));
+        entry.lock_by = Some(worker.clone());
+        Some(req)
+    }
+
+    fn pending(&self) -> usize {
+        self.jobs.values().filter(|e| e.is_pending()).count()
+    }
+
+    /// When the earliest scheduled job is due, if any
+    #[cfg(feature = "sleep")]
+    fn next_run_at(&self) -> Option<i64> {
+        self.scheduled.peek().map(|Reverse((run_at, _))| *run_at)
+    }
+}
+
+impl<T: Clone> Entry<T> {
+    /// A copy of the job, unless it is locked by a worker
+    fn request(&self) -> Option<Request<T>> {
+        let mut req = Request::new(self.job.clone()?);
+        req.insert(self.id.clone());
+        req.insert(Attempt::new_with_value(self.attempts));
+        Some(req)
+    }
+}
+
+/// The current unix time in seconds
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| i64::try_from(d.as_secs()).unwrap_or(i64::MAX))
+        .unwrap_or_default()
+}
+
+fn not_found(id: &TaskId) -> Error {
+    Error::Io(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("Job {id} not found"),
+    ))
+}
+
 /// In-memory queue that implements [Stream]
+#[deprecated(note = "Use MemoryStorage, which also implements Backend and MessageQueue")]
 #[derive(Debug)]
 pub struct MemoryWrapper<T> {
     sender: Sender<T>,
     receiver: Arc<futures::lock::Mutex<Receiver<T>>>,
 }
 
+#[allow(deprecated)]
 impl<T> Clone for MemoryWrapper<T> {
     fn clone(&self) -> Self {
         Self {
@@ -65,6 +230,7 @@ impl<T> Clone for MemoryWrapper<T> {
     }
 }
 
+#[allow(deprecated)]
 impl<T> MemoryWrapper<T> {
     /// Build a new basic queue channel
     pub fn new() -> Self {
@@ -77,12 +243,14 @@ impl<T> MemoryWrapper<T> {
     }
 }
 
+#[allow(deprecated)]
 impl<T> Default for MemoryWrapper<T> {
     fn default() -> Self {
         Self::new()
     }
 }
 
+#[allow(deprecated)]
 impl<T> Stream for MemoryWrapper<T> {
     type Item = T;
 
@@ -99,14 +267,40 @@ impl<T> Stream for MemoryWrapper<T> {
 impl<T: Send + 'static + Sync> Backend<Request<T>> for MemoryStorage<T> {
     type Stream = BackendStream<RequestStream<Request<T>>>;
 
-    type Layer = ServiceBuilder<Identity>;
+    type Layer = AckLayer<MemoryStorage<T>, T>;
 
-    fn common_layer(&self, _worker: WorkerId) -> Self::Layer {
-        ServiceBuilder::new()
+    fn common_layer(&self, worker: WorkerId) -> Self::Layer {
+        AckLayer::new(self.clone(), worker)
     }
 
-    fn poll(self, _worker: WorkerId) -> Poller<Self::Stream> {
-        let stream = self.inner.map(|r| Ok(Some(Request::new(r)))).boxed();
+    fn poll(self, worker: WorkerId) -> Poller<Self::Stream> {
+        let storage = self.clone();
+        #[cfg(feature = "sleep")]
+        let mut timer: Option<(i64, futures_timer::Delay)> = None;
+        let stream = futures::stream::poll_fn(move |cx| {
+            let mut queue = storage.queue();
+            if let Some(req) = queue.lock_next(&worker) {
+                return Poll::Ready(Some(Ok(Some(req))));
+            }
+            if !queue.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                queue.wakers.push(cx.waker().clone());
+            }
+            #[cfg(feature = "sleep")]
+            if let Some(run_at) = queue.next_run_at() {
+                let wait = Duration::from_secs(run_at.saturating_sub(now()).max(0) as u64);
+                let delay = match &mut timer {
+                    Some((at, delay)) if *at == run_at => delay,
+                    _ => &mut timer.insert((run_at, futures_timer::Delay::new(wait))).1,
+                };
+                if futures::FutureExt::poll_unpin(delay, cx).is_ready() {
+                    // Poll again now that the earliest scheduled job is due
+                    timer = None;
+                    cx.waker().wake_by_ref();
+                }
+            }
+            Poll::Pending
+        })
+        .boxed();
         Poller {
             stream: BackendStream::new(stream, self.controller),
             heartbeat: Box::pin(async {}),
@@ -114,19 +308,177 @@ impl<T: Send + 'static + Sync> Backend<Request<T>> for MemoryStorage<T> {
     }
 }
 
+impl<T: Send + Sync> Ack<T> for MemoryStorage<T> {
+    type Acknowledger = TaskId;
+    type Error = Error;
+
+    async fn ack(&self, worker_id: &WorkerId, task_id: &TaskId) -> Result<(), Error> {
+        let mut queue = self.queue();
+        let entry = queue
+            .jobs
+            .get_mut(&task_id.inner())
+            .ok_or_else(|| not_found(task_id))?;
+        if entry.lock_by.as_ref() == Some(worker_id) {
+            entry.lock_by = None;
+            entry.done = true;
+        }
+        Ok(())
+    }
+}
+
+/// [Storage::fetch_by_id] hands out a copy of a job that stays queued, so jobs must be [Clone].
+/// Polling as a [Backend] or dequeuing as a [MessageQueue] moves jobs out and needs no [Clone].
+impl<T: Job + Clone + Send + Sync + 'static> Storage for MemoryStorage<T> {
+    type Job = T;
+
+    type Error = Error;
+
+    type Identifier = TaskId;
+
+    async fn push(&mut self, job: T) -> Result<TaskId, Error> {
+        Ok(self.insert(job, now()))
+    }
+
+    async fn schedule(&mut self, job: T, on: i64) -> Result<TaskId, Error> {
+        Ok(self.insert(job, on))
+    }
+
+    async fn len(&self) -> Result<i64, Error> {
+        Ok(i64::try_from(self.queue().pending()).unwrap_or(i64::MAX))
+    }
+
+    async fn fetch_by_id(&self, job_id: &TaskId) -> Result<Option<Request<T>>, Error> {
+        Ok(self
+            .queue()
+            .jobs
+            .get(&job_id.inner())
+            .and_then(Entry::request))
+    }
+
+    async fn update(&self, job: Request<T>) -> Result<(), Error> {
+        let task_id = job
+            .get::<TaskId>()
+            .ok_or_else(|| Error::InvalidContext("Missing TaskId".to_string()))?;
+        let attempts = job.get::<Attempt>().map(Attempt::current);
+        let mut queue = self.queue();
+        let entry = queue
+            .jobs
+            .get_mut(&task_id.inner())
+            .ok_or_else(|| not_found(task_id))?;
+        if let Some(attempts) = attempts {
+            entry.attempts = attempts;
+        }
+        entry.job = Some(job.req);
+        Ok(())
+    }
+
+    async fn reschedule(&mut self, job: Request<T>, wait: Duration) -> Result<(), Error> {
+        let task_id = job
+            .get::<TaskId>()
+            .ok_or_else(|| Error::InvalidContext("Missing TaskId".to_string()))?;
+        let id = task_id.inner();
+        let wait = i64::try_from(wait.as_secs()).unwrap_or(i64::MAX);
+        let run_at = now().saturating_add(wait);
+        let mut queue = self.queue();
+        let entry = queue.jobs.get_mut(&id).ok_or_else(|| not_found(task_id))?;
+        if let Some(attempts) = job.get::<Attempt>() {
+            entry.attempts = attempts.current();
+        }
+        entry.job = Some(job.req);
+        entry.lock_by = None;
+        entry.done = false;
+        entry.run_at = run_at;
+        queue.enqueue(id, run_at);
+        Ok(())
+    }
+
+    async fn is_empty(&self) -> Result<bool, Error> {
+        Ok(self.queue().pending() == 0)
+    }
+
+    async fn vacuum(&self) -> Result<usize, Error> {
+        let mut queue = self.queue();
+        let before = queue.jobs.len();
+        queue.jobs.retain(|_, entry| !entry.done);
+        Ok(before - queue.jobs.len())
+    }
+}
+
 impl<Message: Send + 'static + Sync> MessageQueue<Message> for MemoryStorage<Message> {
     type Error = ();
     async fn enqueue(&self, message: Message) -> Result<(), Self::Error> {
-        self.inner.sender.clone().try_send(message).unwrap();
+        self.insert(message, now());
         Ok(())
     }
 
     async fn dequeue(&self) -> Result<Option<Message>, ()> {
-        Err(())
-        // self.inner.receiver.lock().await.next().await
+        let mut queue = self.queue();
+        Ok(queue
+            .next_due()
+            .and_then(|id| queue.jobs.remove(&id))
+            .and_then(|entry| entry.job))
     }
 
     async fn size(&self) -> Result<usize, ()> {
-        Ok(self.inner.clone().count().await)
+        Ok(self.queue().pending())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Email(&'static str);
+
+    impl Job for Email {
+        const NAME: &'static str = "apalis::test::Email";
+    }
+
+    #[tokio::test]
+    async fn it_locks_acks_and_reschedules_jobs() {
+        let mut storage = MemoryStorage::new();
+        let worker = WorkerId::new("test-worker");
+        let id = storage.push(Email("now")).await.unwrap();
+        storage
+            .schedule(Email("later"), now() + 3600)
+            .await
+            .unwrap();
+        assert_eq!(storage.len().await.unwrap(), 2);
+
+        storage.controller.plug();
+        let mut stream = storage.clone().poll(worker.clone()).stream;
+        let job = stream.next().await.unwrap().unwrap().unwrap();
+        assert_eq!(job.inner(), &Email("now"));
+        assert_eq!(storage.len().await.unwrap(), 1);
+        assert!(futures::poll!(stream.next()).is_pending());
+
+        job.get::<Attempt>().unwrap().increment();
+        storage.reschedule(job, Duration::ZERO).await.unwrap();
+        let job = stream.next().await.unwrap().unwrap().unwrap();
+        assert_eq!(job.get::<TaskId>().unwrap().inner(), id.inner());
+        assert_eq!(job.get::<Attempt>().unwrap().current(), 1);
+
+        storage.ack(&worker, &id).await.unwrap();
+        assert_eq!(storage.vacuum().await.unwrap(), 1);
+        assert!(storage.fetch_by_id(&id).await.unwrap().is_none());
+        assert_eq!(storage.len().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn it_wakes_a_waiting_stream_on_push() {
+        let mut storage = MemoryStorage::new();
+        storage.controller.plug();
+        let mut stream = storage.clone().poll(WorkerId::new("test-worker")).stream;
+        assert!(futures::poll!(stream.next()).is_pending());
+
+        storage.push(Email("woken")).await.unwrap();
+        let job = tokio::time::timeout(Duration::from_secs(1), stream.next())
+            .await
+            .expect("the push should wake the stream")
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(job.inner(), &Email("woken"));
     }
 }
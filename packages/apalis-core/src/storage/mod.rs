@@ -75,4 +75,47 @@ pub trait Storage: Backend<Request<Self::Job>> {
 pub trait Job {
     /// Represents the name for job.
     const NAME: &'static str;
+
+    /// Every name a job of this type can be stored under.
+    ///
+    /// Types that vary [`Job::name_for`] by value, eg an enum of tasks,
+    /// must list all of the names here so storages fetch every variant. Defaults to [`Job::NAME`].
+    const NAMES: &'static [&'static str] = &[Self::NAME];
+
+    /// The name this particular job is stored under. Defaults to [`Job::NAME`].
+    fn name_for(&self) -> &str {
+        Self::NAME
+    }
+
+    /// The maximum attempts for this particular job.
+    ///
+    /// When `None`, the storage default is used. Storages that support it retry a job
+    /// with a maximum of `0` attempts until it succeeds. Defaults to `None`.
+    fn max_attempts_for(&self) -> Option<usize> {
+        None
+    }
+
+    /// The key of jobs that are redundant while one of them is pending, eg the user to recompute a dashboard for.
+    ///
+    /// When `Some`, storages that support it coalesce a pushed job into the pending job
+    /// with the same key instead of queueing another one. Defaults to `None`.
+    fn dedup_key(&self) -> Option<String> {
+        None
+    }
+
+    /// The delay after which a completed job should run again.
+    ///
+    /// When `Some`, storages that support it schedule a fresh copy of the job
+    /// after the delay as part of acknowledging it. Defaults to `None`.
+    fn reschedule_after() -> Option<Duration> {
+        None
+    }
+
+    /// The execution budget for a single run of the job.
+    ///
+    /// When `Some`, storages that support it fail running jobs that have been locked
+    /// for longer than the budget instead of waiting for orphan recovery. Defaults to `None`.
+    fn timeout() -> Option<Duration> {
+        None
+    }
 }
@@ -2,3 +2,5 @@
 pub mod attempt;
 /// A unique ID that can be used by a backend
 pub mod task_id;
+/// A timestamp shared by every backend
+pub mod timestamp;
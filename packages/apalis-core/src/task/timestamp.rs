@@ -0,0 +1,89 @@
+use std::{
+    fmt::{self, Display},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// A point in time as milliseconds since the unix epoch, in UTC.
+///
+/// The representation every backend converts its own timestamp columns to and from,
+/// so the timestamps of a task read the same regardless of where it is stored.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Timestamp(i64);
+
+impl Timestamp {
+    /// The current time of the system clock
+    pub fn now() -> Self {
+        SystemTime::now().into()
+    }
+
+    /// Build a timestamp from milliseconds since the unix epoch
+    pub fn from_millis(millis: i64) -> Self {
+        Self(millis)
+    }
+
+    /// Build a timestamp from seconds since the unix epoch
+    pub fn from_secs(secs: i64) -> Self {
+        Self(secs.saturating_mul(1000))
+    }
+
+    /// Get the milliseconds since the unix epoch
+    pub fn as_millis(&self) -> i64 {
+        self.0
+    }
+
+    /// Get the whole seconds since the unix epoch, rounding down
+    pub fn as_secs(&self) -> i64 {
+        self.0.div_euclid(1000)
+    }
+
+    /// The time elapsed from `earlier` to this timestamp, or `None` if `earlier` is later
+    pub fn duration_since(&self, earlier: Timestamp) -> Option<Duration> {
+        let millis = self.0.checked_sub(earlier.0)?;
+        u64::try_from(millis).ok().map(Duration::from_millis)
+    }
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        let millis = match time.duration_since(UNIX_EPOCH) {
+            Ok(after) => i64::try_from(after.as_millis()).unwrap_or(i64::MAX),
+            Err(before) => -i64::try_from(before.duration().as_millis()).unwrap_or(i64::MAX),
+        };
+        Self(millis)
+    }
+}
+
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_converts_between_seconds_and_millis() {
+        let timestamp = Timestamp::from_secs(1_700_000_000);
+        assert_eq!(timestamp.as_millis(), 1_700_000_000_000);
+        assert_eq!(
+            Timestamp::from_millis(1_700_000_000_999).as_secs(),
+            1_700_000_000
+        );
+        assert_eq!(Timestamp::from_millis(-1).as_secs(), -1);
+        assert_eq!(
+            Timestamp::from_millis(1_500).duration_since(Timestamp::from_secs(1)),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(
+            Timestamp::from_secs(1).duration_since(Timestamp::from_millis(1_500)),
+            None
+        );
+    }
+}
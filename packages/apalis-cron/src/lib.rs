@@ -66,15 +66,29 @@ use apalis_core::data::Extensions;
 use apalis_core::request::RequestStream;
 use apalis_core::task::task_id::TaskId;
 use apalis_core::{error::Error, request::Request};
-use chrono::{DateTime, TimeZone, Utc};
+use chrono::{DateTime, Duration, LocalResult, NaiveDateTime, Offset, TimeZone, Utc};
 pub use cron::Schedule;
 use std::marker::PhantomData;
 
+/// What a [CronStream] does with the times it missed, eg while the scheduler was down
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CatchUpPolicy {
+    /// Fire once for every missed time
+    #[default]
+    All,
+    /// Fire once for the latest missed time only
+    LatestOnly,
+    /// Do not fire for missed times, and wait for the next time that is still ahead
+    Skip,
+}
+
 /// Represents a stream from a cron schedule with a timezone
 #[derive(Clone, Debug)]
 pub struct CronStream<J, Tz> {
     schedule: Schedule,
     timezone: Tz,
+    catch_up: CatchUpPolicy,
+    starting_after: Option<DateTime<Utc>>,
     _marker: PhantomData<J>,
 }
 
@@ -84,6 +98,8 @@ impl<J> CronStream<J, Utc> {
         Self {
             schedule,
             timezone: Utc,
+            catch_up: CatchUpPolicy::default(),
+            starting_after: None,
             _marker: PhantomData,
         }
     }
@@ -94,13 +110,125 @@ where
     Tz: TimeZone + Send + Sync + 'static,
 {
     /// Build a new cron stream from a schedule and timezone
+    ///
+    /// The schedule is read as wall clock time in the timezone, eg a `chrono_tz::Tz`,
+    /// so daily jobs keep their local time across daylight saving changes.
     pub fn new_with_timezone(schedule: Schedule, timezone: Tz) -> Self {
         Self {
             schedule,
             timezone,
+            catch_up: CatchUpPolicy::default(),
+            starting_after: None,
             _marker: PhantomData,
         }
     }
+
+    /// Choose what happens to the times missed while the stream was not running or not polled
+    ///
+    /// Defaults to [CatchUpPolicy::All]
+    pub fn with_catch_up(mut self, catch_up: CatchUpPolicy) -> Self {
+        self.catch_up = catch_up;
+        self
+    }
+
+    /// Start from the times due after `last_run` instead of now, eg the last run before a restart
+    ///
+    /// The times between `last_run` and now are handled by the [CatchUpPolicy].
+    pub fn starting_after(mut self, last_run: DateTime<Utc>) -> Self {
+        self.starting_after = Some(last_run);
+        self
+    }
+
+    /// The time to fire next after the last run at `after`, applying the catch-up policy to times before `now`
+    ///
+    /// ```rust
+    /// # use apalis_cron::{CatchUpPolicy, CronStream, Schedule};
+    /// # use chrono::{TimeZone, Utc};
+    /// # use std::str::FromStr;
+    /// let schedule = Schedule::from_str("0 0 * * * *").unwrap();
+    /// let last_run = Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap();
+    /// let now = Utc.with_ymd_and_hms(2024, 1, 1, 12, 30, 0).unwrap();
+    /// let all: CronStream<(), _> = CronStream::new(schedule.clone());
+    /// assert_eq!(all.next_due(&last_run, &now).unwrap().to_rfc3339(), "2024-01-01T10:00:00+00:00");
+    /// let latest = CronStream::<(), _>::new(schedule.clone()).with_catch_up(CatchUpPolicy::LatestOnly);
+    /// assert_eq!(latest.next_due(&last_run, &now).unwrap().to_rfc3339(), "2024-01-01T12:00:00+00:00");
+    /// let skip = CronStream::<(), _>::new(schedule).with_catch_up(CatchUpPolicy::Skip);
+    /// assert_eq!(skip.next_due(&last_run, &now).unwrap().to_rfc3339(), "2024-01-01T13:00:00+00:00");
+    /// ```
+    pub fn next_due(&self, after: &DateTime<Utc>, now: &DateTime<Utc>) -> Option<DateTime<Tz>> {
+        let next = self.next_after(after)?;
+        if next.with_timezone(&Utc) > *now {
+            return Some(next);
+        }
+        match self.catch_up {
+            CatchUpPolicy::All => Some(next),
+            CatchUpPolicy::LatestOnly => {
+                let mut latest = next;
+                while let Some(following) = self.next_after(&latest.with_timezone(&Utc)) {
+                    if following.with_timezone(&Utc) > *now {
+                        break;
+                    }
+                    latest = following;
+                }
+                Some(latest)
+            }
+            CatchUpPolicy::Skip => self.next_after(now),
+        }
+    }
+
+    /// The first time the schedule is due after `after`
+    ///
+    /// Use `with_timezone(&Utc)` on the result to get a UTC time, eg a `run_at` for a storage.
+    /// Local times that happen twice when the clocks go back are only due once, at the first occurrence.
+    /// Local times skipped when the clocks go forward are due as if the clocks had not changed yet.
+    ///
+    /// ```rust
+    /// # use apalis_cron::{CronStream, Schedule};
+    /// # use chrono::{TimeZone, Utc};
+    /// # use std::str::FromStr;
+    /// let schedule = Schedule::from_str("0 30 2 * * *").unwrap();
+    /// let stream: CronStream<(), _> = CronStream::new_with_timezone(schedule, chrono_tz::Europe::Berlin);
+    /// // Clocks skip from 02:00 to 03:00 on the 31st of March 2024, so 02:30 runs at 03:30
+    /// let after = Utc.with_ymd_and_hms(2024, 3, 30, 12, 0, 0).unwrap();
+    /// let next = stream.next_after(&after).unwrap();
+    /// assert_eq!(next.to_rfc3339(), "2024-03-31T03:30:00+02:00");
+    /// assert_eq!(next.with_timezone(&Utc), Utc.with_ymd_and_hms(2024, 3, 31, 1, 30, 0).unwrap());
+    /// // Clocks go back from 03:00 to 02:00 on the 27th of October 2024, and 02:30 only runs once
+    /// let after = Utc.with_ymd_and_hms(2024, 10, 26, 12, 0, 0).unwrap();
+    /// let next = stream.next_after(&after).unwrap();
+    /// assert_eq!(next.to_rfc3339(), "2024-10-27T02:30:00+02:00");
+    /// let next = stream.next_after(&next.with_timezone(&Utc)).unwrap();
+    /// assert_eq!(next.to_rfc3339(), "2024-10-28T02:30:00+01:00");
+    /// ```
+    pub fn next_after(&self, after: &DateTime<Utc>) -> Option<DateTime<Tz>> {
+        // Cron fields describe the wall clock, so walk the schedule in local time
+        let local = after.with_timezone(&self.timezone).naive_local();
+        self.schedule
+            .after(&Utc.from_utc_datetime(&local))
+            .filter_map(|wall| resolve_local(&self.timezone, &wall.naive_utc()))
+            .find(|next| next.with_timezone(&Utc) > *after)
+    }
+}
+
+/// Resolve a local wall clock time in a timezone, including times that are repeated or skipped
+fn resolve_local<Tz: TimeZone>(timezone: &Tz, local: &NaiveDateTime) -> Option<DateTime<Tz>> {
+    match timezone.from_local_datetime(local) {
+        LocalResult::Single(time) => Some(time),
+        LocalResult::Ambiguous(earliest, _) => Some(earliest),
+        LocalResult::None => {
+            // Mapping the time with one of the offsets around the gap lands on the
+            // instant that uses the other one. The clocks went forward, so the offset before is smaller.
+            let offset_at = |offset: i32| {
+                timezone
+                    .offset_from_utc_datetime(&(*local - Duration::seconds(offset.into())))
+                    .fix()
+                    .local_minus_utc()
+            };
+            let first = offset_at(0);
+            let before = first.min(offset_at(first));
+            Some(timezone.from_utc_datetime(&(*local - Duration::seconds(before.into()))))
+        }
+    }
 }
 impl<J, Tz> CronStream<J, Tz>
 where
@@ -112,12 +240,14 @@ where
     pub fn into_stream(self) -> RequestStream<Request<J>> {
         let timezone = self.timezone.clone();
         let stream = async_stream::stream! {
-            let mut schedule = self.schedule.upcoming_owned(timezone.clone());
+            let mut after = self.starting_after.unwrap_or_else(Utc::now);
             loop {
-                let next = schedule.next();
+                let next = self.next_due(&after, &Utc::now());
                 match next {
                     Some(next) => {
-                        let to_sleep = next - timezone.from_utc_datetime(&Utc::now().naive_utc());
+                        after = next.with_timezone(&Utc);
+                        // Missed times and skipped local times are due right away
+                        let to_sleep = (after - Utc::now()).max(Duration::zero());
                         let to_sleep = to_sleep.to_std().map_err(|e| Error::Failed(e.into()))?;
                         apalis_core::sleep(to_sleep).await;
                         let mut data = Extensions::new();
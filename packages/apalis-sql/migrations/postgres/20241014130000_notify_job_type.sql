@@ -0,0 +1,10 @@
+CREATE OR REPLACE FUNCTION apalis.notify_new_jobs() returns trigger as $$
+    BEGIN
+        perform pg_notify('apalis::job', NEW.job_type);
+        return new;
+    END;
+$$ language plpgsql;
+
+DROP TRIGGER IF EXISTS notify_workers ON apalis.jobs;
+
+CREATE TRIGGER notify_workers after insert on apalis.jobs for each row execute procedure apalis.notify_new_jobs();
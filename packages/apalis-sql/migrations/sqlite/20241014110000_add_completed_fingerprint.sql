@@ -0,0 +1,3 @@
+ALTER TABLE Jobs ADD COLUMN completed_fingerprint TEXT;
+
+CREATE UNIQUE INDEX IF NOT EXISTS CFIdx ON Jobs(completed_fingerprint);
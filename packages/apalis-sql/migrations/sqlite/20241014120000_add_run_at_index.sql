@@ -0,0 +1 @@
+CREATE INDEX IF NOT EXISTS RIdx ON Jobs(run_at);
@@ -0,0 +1 @@
+ALTER TABLE Jobs ADD COLUMN attempt_errors TEXT;
@@ -0,0 +1,7 @@
+ALTER TABLE Jobs ADD COLUMN version INTEGER NOT NULL DEFAULT 0;
+
+CREATE TRIGGER IF NOT EXISTS JobsVersion AFTER UPDATE ON Jobs
+    WHEN NEW.version = OLD.version
+BEGIN
+    UPDATE Jobs SET version = OLD.version + 1 WHERE rowid = NEW.rowid;
+END;
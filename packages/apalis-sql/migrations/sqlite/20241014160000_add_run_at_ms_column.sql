@@ -0,0 +1 @@
+ALTER TABLE Jobs ADD COLUMN run_at_ms INTEGER;
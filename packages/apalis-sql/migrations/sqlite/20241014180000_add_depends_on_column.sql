@@ -0,0 +1 @@
+ALTER TABLE Jobs ADD COLUMN depends_on TEXT;
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS CircuitBreakers (
+    job_type TEXT NOT NULL PRIMARY KEY,
+    failures INTEGER NOT NULL DEFAULT 0,
+    window_start INTEGER NOT NULL,
+    open_until INTEGER
+);
@@ -0,0 +1 @@
+ALTER TABLE Jobs ADD COLUMN affinity_key TEXT;
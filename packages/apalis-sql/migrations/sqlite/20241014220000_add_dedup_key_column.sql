@@ -0,0 +1,3 @@
+ALTER TABLE Jobs ADD COLUMN dedup_key TEXT;
+
+CREATE UNIQUE INDEX IF NOT EXISTS DKIdx ON Jobs(job_type, IFNULL(namespace, ''), dedup_key) WHERE dedup_key IS NOT NULL AND status = 'Pending';
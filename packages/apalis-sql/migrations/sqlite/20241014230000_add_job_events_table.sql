@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS JobEvents (
+    job_id TEXT NOT NULL,
+    from_state TEXT,
+    to_state TEXT NOT NULL,
+    worker_id TEXT,
+    at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS JEIdx ON JobEvents(job_id);
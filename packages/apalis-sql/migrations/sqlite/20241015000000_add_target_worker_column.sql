@@ -0,0 +1 @@
+ALTER TABLE Jobs ADD COLUMN target_worker TEXT;
@@ -0,0 +1,83 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use sqlx::types::chrono::{DateTime, Utc};
+
+/// A source of the current time for a storage
+///
+/// Everything a storage decides by time, such as when a scheduled job is due, when a lock
+/// is orphaned or when a failed job may be retried, is measured against its clock.
+pub trait Clock {
+    /// The current time
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// A [Clock] reading the system time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A [Clock] that only moves when told to, for deterministic tests
+///
+/// Clones share the same time, so a test can keep one to advance the clock of a storage.
+/// The time has millisecond precision.
+#[derive(Clone)]
+pub struct MockClock {
+    now_ms: Arc<AtomicI64>,
+}
+
+impl MockClock {
+    /// A clock stopped at the given time
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            now_ms: Arc::new(AtomicI64::new(now.timestamp_millis())),
+        }
+    }
+
+    /// Move the clock to the given time, which may be in its past
+    pub fn set(&self, now: DateTime<Utc>) {
+        self.now_ms.store(now.timestamp_millis(), Ordering::SeqCst);
+    }
+
+    /// Move the clock forward by `by`
+    pub fn advance(&self, by: Duration) {
+        let by = i64::try_from(by.as_millis()).unwrap_or(i64::MAX);
+        let _ = self
+            .now_ms
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |now| {
+                Some(now.saturating_add(by))
+            });
+    }
+}
+
+impl Default for MockClock {
+    /// A clock stopped at the current system time
+    fn default() -> Self {
+        Self::new(Utc::now())
+    }
+}
+
+impl fmt::Debug for MockClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockClock")
+            .field("now", &self.now())
+            .finish()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp_millis(self.now_ms.load(Ordering::SeqCst)).unwrap_or_default()
+    }
+}
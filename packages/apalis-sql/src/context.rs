@@ -1,9 +1,9 @@
 use apalis_core::error::Error;
-use apalis_core::task::{attempt::Attempt, task_id::TaskId};
+use apalis_core::task::{attempt::Attempt, task_id::TaskId, timestamp::Timestamp};
 use apalis_core::worker::WorkerId;
 use serde::{Deserialize, Serialize};
 use sqlx::types::chrono::{DateTime, Utc};
-use std::{fmt, str::FromStr};
+use std::{fmt, str::FromStr, time::Duration};
 
 /// The context for a job is represented here
 /// Used to provide a context when a job is defined through the [Job] trait
@@ -15,9 +15,15 @@ pub struct SqlContext {
     attempts: Attempt,
     max_attempts: i32,
     last_error: Option<String>,
-    lock_at: Option<i64>,
+    lock_at: Option<Timestamp>,
     lock_by: Option<WorkerId>,
-    done_at: Option<i64>,
+    done_by: Option<WorkerId>,
+    done_at: Option<Timestamp>,
+    priority: i32,
+    tenant: Option<String>,
+    affinity_key: Option<String>,
+    error_history: Vec<AttemptError>,
+    version: i64,
 }
 
 impl SqlContext {
@@ -33,15 +39,21 @@ impl SqlContext {
             max_attempts: 25,
             last_error: None,
             lock_by: None,
+            done_by: None,
+            priority: 0,
+            tenant: None,
+            affinity_key: None,
+            error_history: Vec::new(),
+            version: 0,
         }
     }
 
-    /// Set the number of attempts
+    /// Set the number of attempts, where 0 retries the job until it succeeds
     pub fn set_max_attempts(&mut self, max_attempts: i32) {
         self.max_attempts = max_attempts;
     }
 
-    /// Gets the maximum attempts for a job. Default 25
+    /// Gets the maximum attempts for a job, 0 if the job is retried until it succeeds. Default 25
     pub fn max_attempts(&self) -> i32 {
         self.max_attempts
     }
@@ -62,12 +74,12 @@ impl SqlContext {
     }
 
     /// Get the time a job was done
-    pub fn done_at(&self) -> &Option<i64> {
+    pub fn done_at(&self) -> &Option<Timestamp> {
         &self.done_at
     }
 
     /// Set the time a job was done
-    pub fn set_done_at(&mut self, done_at: Option<i64>) {
+    pub fn set_done_at(&mut self, done_at: Option<Timestamp>) {
         self.done_at = done_at;
     }
 
@@ -82,12 +94,12 @@ impl SqlContext {
     }
 
     /// Get the time a job was locked
-    pub fn lock_at(&self) -> &Option<i64> {
+    pub fn lock_at(&self) -> &Option<Timestamp> {
         &self.lock_at
     }
 
     /// Set the lock_at value
-    pub fn set_lock_at(&mut self, lock_at: Option<i64>) {
+    pub fn set_lock_at(&mut self, lock_at: Option<Timestamp>) {
         self.lock_at = lock_at;
     }
 
@@ -111,6 +123,16 @@ impl SqlContext {
         self.lock_by = lock_by;
     }
 
+    /// Get the worker that completed or killed the job
+    pub fn done_by(&self) -> &Option<WorkerId> {
+        &self.done_by
+    }
+
+    /// Set `done_by`
+    pub fn set_done_by(&mut self, done_by: Option<WorkerId>) {
+        self.done_by = done_by;
+    }
+
     /// Get the time a job was locked
     pub fn last_error(&self) -> &Option<String> {
         &self.last_error
@@ -121,10 +143,140 @@ impl SqlContext {
         self.last_error = Some(error);
     }
 
+    /// Get the priority of a job. Higher values are more urgent. Default 0
+    pub fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    /// Set the priority of a job
+    pub fn set_priority(&mut self, priority: i32) {
+        self.priority = priority;
+    }
+
+    /// Get the tenant a job belongs to, used by fair scheduling
+    pub fn tenant(&self) -> &Option<String> {
+        &self.tenant
+    }
+
+    /// Set the tenant a job belongs to
+    pub fn set_tenant(&mut self, tenant: Option<String>) {
+        self.tenant = tenant;
+    }
+
+    /// Get the key routing a job to the same worker as other jobs with that key
+    pub fn affinity_key(&self) -> &Option<String> {
+        &self.affinity_key
+    }
+
+    /// Set the key routing a job to the same worker as other jobs with that key
+    pub fn set_affinity_key(&mut self, affinity_key: Option<String>) {
+        self.affinity_key = affinity_key;
+    }
+
+    /// Get the errors of previous failed attempts, oldest first
+    pub fn error_history(&self) -> &[AttemptError] {
+        &self.error_history
+    }
+
+    /// Set the errors of previous failed attempts
+    pub fn set_error_history(&mut self, error_history: Vec<AttemptError>) {
+        self.error_history = error_history;
+    }
+
+    /// Get the version of the job, increased by every change to it
+    pub fn version(&self) -> i64 {
+        self.version
+    }
+
+    /// Set the version of the job
+    pub fn set_version(&mut self, version: i64) {
+        self.version = version;
+    }
+
     /// Record an attempt to execute the request
     pub fn record_attempt(&mut self) {
         self.attempts.increment();
     }
+
+    /// Gets how many attempts the job has left, counting the current one. Never below 0
+    ///
+    /// Jobs with unlimited attempts always have [i32::MAX] attempts left
+    pub fn attempts_remaining(&self) -> i32 {
+        if self.max_attempts == 0 {
+            return i32::MAX;
+        }
+        let attempts = i32::try_from(self.attempts.current()).unwrap_or(i32::MAX);
+        self.max_attempts.saturating_sub(attempts).max(0)
+    }
+
+    /// Whether the job will not be retried if the current attempt fails
+    pub fn is_last_attempt(&self) -> bool {
+        self.attempts_remaining() <= 1
+    }
+
+    /// Gets how long the last run took, from the worker locking the job until it was done
+    ///
+    /// Returns `None` unless both times are known and the job was done after it was last locked
+    pub fn run_duration(&self) -> Option<Duration> {
+        self.done_at?.duration_since(self.lock_at?)
+    }
+
+    /// Gets how long the job waited to be locked after it was due
+    ///
+    /// Jobs have no creation time, so the wait is measured from `run_at`.
+    /// Returns `None` if the job is not locked, or was locked before it was last due
+    pub fn wait_duration(&self) -> Option<Duration> {
+        let run_at = Timestamp::from_millis(self.run_at.timestamp_millis());
+        self.lock_at?.duration_since(run_at)
+    }
+
+    /// Preview when the job would run again if it was rescheduled at `now` to wait for `wait`
+    ///
+    /// Pass the time of the storage's clock, which is what rescheduling uses.
+    /// Returns `None` if the job has no attempts left
+    pub fn next_retry_at(&self, wait: Duration, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let attempts = i32::try_from(self.attempts.current()).unwrap_or(i32::MAX);
+        if self.max_attempts != 0 && attempts >= self.max_attempts {
+            return None;
+        }
+        retry_at(now, wait)
+    }
+}
+
+/// The time a job rescheduled at `now` to wait for `wait` should run
+pub(crate) fn retry_at(now: DateTime<Utc>, wait: Duration) -> Option<DateTime<Utc>> {
+    let wait = i64::try_from(wait.as_secs()).ok()?;
+    DateTime::from_timestamp(now.timestamp().checked_add(wait)?, 0)
+}
+
+/// The error a single failed attempt of a job ended with
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AttemptError {
+    attempt: i32,
+    at: i64,
+    error: String,
+}
+
+impl AttemptError {
+    /// Build a new entry for the error history
+    pub fn new(attempt: i32, at: i64, error: String) -> Self {
+        Self { attempt, at, error }
+    }
+
+    /// The attempt that failed
+    pub fn attempt(&self) -> i32 {
+        self.attempt
+    }
+
+    /// The time the failure was recorded
+    pub fn at(&self) -> i64 {
+        self.at
+    }
+
+    /// The error of the attempt
+    pub fn error(&self) -> &str {
+        &self.error
+    }
 }
 
 /// Represents the state of a [Request]
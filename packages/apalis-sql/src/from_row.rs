@@ -1,4 +1,4 @@
-use apalis_core::task::task_id::TaskId;
+use apalis_core::task::{task_id::TaskId, timestamp::Timestamp};
 use apalis_core::{data::Extensions, request::Request, worker::WorkerId};
 use sqlx::{Decode, Type};
 
@@ -49,10 +49,10 @@ impl<'r, T: Decode<'r, sqlx::Sqlite> + Type<sqlx::Sqlite>>
         context.set_max_attempts(max_attempts);
 
         let done_at: Option<i64> = row.try_get("done_at").unwrap_or_default();
-        context.set_done_at(done_at);
+        context.set_done_at(done_at.map(Timestamp::from_secs));
 
         let lock_at: Option<i64> = row.try_get("lock_at").unwrap_or_default();
-        context.set_lock_at(lock_at);
+        context.set_lock_at(lock_at.map(Timestamp::from_secs));
 
         let last_error = row.try_get("last_error").unwrap_or_default();
         context.set_last_error(last_error);
@@ -75,6 +75,40 @@ impl<'r, T: Decode<'r, sqlx::Sqlite> + Type<sqlx::Sqlite>>
                 })?,
         );
 
+        let done_by: Option<String> = row.try_get("done_by").unwrap_or_default();
+        context.set_done_by(
+            done_by
+                .as_deref()
+                .map(WorkerId::from_str)
+                .transpose()
+                .map_err(|_| sqlx::Error::ColumnDecode {
+                    index: "done_by".to_string(),
+                    source: "Could not parse done_by as a WorkerId".into(),
+                })?,
+        );
+
+        let priority = row.try_get("priority").unwrap_or(0);
+        context.set_priority(priority);
+
+        let tenant: Option<String> = row.try_get("tenant").unwrap_or_default();
+        context.set_tenant(tenant);
+
+        let affinity_key: Option<String> = row.try_get("affinity_key").unwrap_or_default();
+        context.set_affinity_key(affinity_key);
+
+        let version = row.try_get("version").unwrap_or(0);
+        context.set_version(version);
+
+        let attempt_errors: Option<String> = row.try_get("attempt_errors").unwrap_or_default();
+        if let Some(attempt_errors) = attempt_errors {
+            context.set_error_history(serde_json::from_str(&attempt_errors).map_err(|e| {
+                sqlx::Error::ColumnDecode {
+                    index: "attempt_errors".to_string(),
+                    source: Box::new(e),
+                }
+            })?);
+        }
+
         Ok(SqlRequest { context, req: job })
     }
 }
@@ -85,9 +119,9 @@ impl<'r, T: Decode<'r, sqlx::Postgres> + Type<sqlx::Postgres>>
     sqlx::FromRow<'r, sqlx::postgres::PgRow> for SqlRequest<T>
 {
     fn from_row(row: &'r sqlx::postgres::PgRow) -> Result<Self, sqlx::Error> {
+        use sqlx::types::chrono::{DateTime, Utc};
         use sqlx::Row;
         use std::str::FromStr;
-        type Timestamp = i64;
 
         let job: T = row.try_get("job")?;
         let id: TaskId =
@@ -106,11 +140,11 @@ impl<'r, T: Decode<'r, sqlx::Postgres> + Type<sqlx::Postgres>>
         let max_attempts = row.try_get("max_attempts").unwrap_or(25);
         context.set_max_attempts(max_attempts);
 
-        let done_at: Option<Timestamp> = row.try_get("done_at").unwrap_or_default();
-        context.set_done_at(done_at);
+        let done_at: Option<DateTime<Utc>> = row.try_get("done_at").unwrap_or_default();
+        context.set_done_at(done_at.map(|t| Timestamp::from_millis(t.timestamp_millis())));
 
-        let lock_at: Option<Timestamp> = row.try_get("lock_at").unwrap_or_default();
-        context.set_lock_at(lock_at);
+        let lock_at: Option<DateTime<Utc>> = row.try_get("lock_at").unwrap_or_default();
+        context.set_lock_at(lock_at.map(|t| Timestamp::from_millis(t.timestamp_millis())));
 
         let last_error = row.try_get("last_error").unwrap_or_default();
         context.set_last_error(last_error);
@@ -142,11 +176,10 @@ impl<'r, T: Decode<'r, sqlx::MySql> + Type<sqlx::MySql>> sqlx::FromRow<'r, sqlx:
     for SqlRequest<T>
 {
     fn from_row(row: &'r sqlx::mysql::MySqlRow) -> Result<Self, sqlx::Error> {
+        use sqlx::types::chrono::{DateTime, Utc};
         use sqlx::Row;
         use std::str::FromStr;
 
-        type Timestamp = i64;
-
         let job: T = row.try_get("job")?;
         let id: TaskId =
             TaskId::from_str(row.try_get("id")?).map_err(|e| sqlx::Error::ColumnDecode {
@@ -164,11 +197,11 @@ impl<'r, T: Decode<'r, sqlx::MySql> + Type<sqlx::MySql>> sqlx::FromRow<'r, sqlx:
         let max_attempts = row.try_get("max_attempts").unwrap_or(25);
         context.set_max_attempts(max_attempts);
 
-        let done_at: Option<Timestamp> = row.try_get("done_at").unwrap_or_default();
-        context.set_done_at(done_at);
+        let done_at: Option<DateTime<Utc>> = row.try_get("done_at").unwrap_or_default();
+        context.set_done_at(done_at.map(|t| Timestamp::from_millis(t.timestamp_millis())));
 
-        let lock_at: Option<Timestamp> = row.try_get("lock_at").unwrap_or_default();
-        context.set_lock_at(lock_at);
+        let lock_at: Option<DateTime<Utc>> = row.try_get("lock_at").unwrap_or_default();
+        context.set_lock_at(lock_at.map(|t| Timestamp::from_millis(t.timestamp_millis())));
 
         let last_error = row.try_get("last_error").unwrap_or_default();
         context.set_last_error(last_error);
@@ -10,12 +10,26 @@
 //! apalis offers Sqlite, Mysql and Postgres storages for its workers.
 //! See relevant modules for examples
 
-use std::time::Duration;
+use std::{fmt, time::Duration};
 
+use apalis_core::error::BoxDynError;
+use apalis_core::task::{task_id::TaskId, timestamp::Timestamp};
+use apalis_core::worker::WorkerId;
+use context::State;
+use sqlx::types::chrono::{DateTime, Utc};
+
+/// Clocks a storage measures time with
+pub mod clock;
 /// The context of the sql job
 pub mod context;
 /// Util for fetching rows
 pub mod from_row;
+#[cfg(all(feature = "otel", feature = "sqlite"))]
+mod otel;
+/// External stores for large job payloads
+pub mod payload;
+#[cfg(all(feature = "webhooks", feature = "sqlite"))]
+mod webhook;
 
 /// Postgres storage for apalis. Uses `NOTIFY` and `SKIP LOCKED`
 #[cfg(feature = "postgres")]
@@ -34,11 +48,36 @@ pub mod sqlite;
 pub mod mysql;
 
 /// Config for sql storages
+///
+/// Every storage uses the poll interval, keep alive and buffer size.
+/// The other options are currently only used by the sqlite storage.
 #[derive(Debug, Clone)]
 pub struct Config {
     keep_alive: Duration,
     buffer_size: usize,
     poll_interval: Duration,
+    fetch_strategy: FetchStrategy,
+    aging_factor: Duration,
+    prioritize_orphans: bool,
+    scheduled_scan_interval: Option<Duration>,
+    fairness_key: Option<String>,
+    worker_type: Option<String>,
+    storage_name: Option<String>,
+    min_priority: Option<i32>,
+    heartbeat_batch_size: Option<usize>,
+    delete_on_ack: bool,
+    poll_jitter: f64,
+    payload_threshold: Option<usize>,
+    max_payload_bytes: Option<usize>,
+    slow_query_threshold: Option<Duration>,
+    max_concurrent: Option<usize>,
+    millisecond_run_at: bool,
+    failed_cooldown: Duration,
+    prune_workers_after: Duration,
+    circuit_breaker: Option<CircuitBreaker>,
+    stop_after_empty_polls: usize,
+    affinity_routing: Option<Duration>,
+    coalesce_mode: CoalesceMode,
 }
 
 impl Default for Config {
@@ -47,6 +86,28 @@ impl Default for Config {
             keep_alive: Duration::from_secs(30),
             buffer_size: 10,
             poll_interval: Duration::from_millis(50),
+            fetch_strategy: FetchStrategy::default(),
+            aging_factor: Duration::from_secs(60),
+            prioritize_orphans: false,
+            scheduled_scan_interval: None,
+            fairness_key: None,
+            worker_type: None,
+            storage_name: None,
+            min_priority: None,
+            heartbeat_batch_size: None,
+            delete_on_ack: false,
+            poll_jitter: 0.0,
+            payload_threshold: None,
+            max_payload_bytes: None,
+            slow_query_threshold: None,
+            max_concurrent: None,
+            millisecond_run_at: false,
+            failed_cooldown: Duration::ZERO,
+            prune_workers_after: Duration::from_secs(24 * 60 * 60),
+            circuit_breaker: None,
+            stop_after_empty_polls: 3,
+            affinity_routing: None,
+            coalesce_mode: CoalesceMode::default(),
         }
     }
 }
@@ -75,4 +136,678 @@ impl Config {
         self.buffer_size = buffer_size;
         self
     }
+
+    /// The order in which eligible jobs are picked when polling
+    ///
+    /// Defaults to [FetchStrategy::Fifo]
+    pub fn fetch_strategy(mut self, fetch_strategy: FetchStrategy) -> Self {
+        self.fetch_strategy = fetch_strategy;
+        self
+    }
+
+    /// How long a job has to wait in the queue for its priority to rise by one
+    ///
+    /// Only used by [FetchStrategy::PriorityWithAging]. Values below a second are rounded up to one.
+    ///
+    /// Defaults to 60 seconds
+    pub fn aging_factor(mut self, aging_factor: Duration) -> Self {
+        self.aging_factor = aging_factor;
+        self
+    }
+
+    /// Reclaim orphaned jobs with the highest priority first, so they are rescheduled soonest
+    ///
+    /// Defaults to false, reclaiming the longest locked jobs first
+    pub fn prioritize_orphans(mut self, prioritize_orphans: bool) -> Self {
+        self.prioritize_orphans = prioritize_orphans;
+        self
+    }
+
+    /// Interval between full scans for due jobs, including long scheduled ones
+    ///
+    /// In between, polls only look at jobs that became due since the last full scan.
+    /// A full batch always triggers another full scan so backlogs keep draining.
+    ///
+    /// Defaults to none, where every poll is a full scan
+    pub fn scheduled_scan_interval(mut self, interval: Duration) -> Self {
+        self.scheduled_scan_interval = Some(interval);
+        self
+    }
+
+    /// Round-robin polled jobs across the distinct values of a column, eg. a tenant
+    ///
+    /// Each poll takes the first job of every value before the second of any,
+    /// ordered within a value by the [FetchStrategy]. Jobs with a NULL key share one bucket.
+    /// The sqlite jobs table has a `tenant` column for this, set through the job context.
+    ///
+    /// Defaults to none, ordering purely by the [FetchStrategy]
+    pub fn fairness_key(mut self, column: impl Into<String>) -> Self {
+        self.fairness_key = Some(column.into());
+        self
+    }
+
+    /// The type workers register under, eg. to tell a GPU pool from a CPU pool consuming the same jobs
+    ///
+    /// Orphaned jobs are only reclaimed from dead workers of the same type.
+    ///
+    /// Defaults to the job type
+    pub fn worker_type(mut self, worker_type: impl Into<String>) -> Self {
+        self.worker_type = Some(worker_type.into());
+        self
+    }
+
+    /// The storage name workers register under, eg. to query the workers of one storage
+    ///
+    /// Defaults to the type name of the storage, which is not stable across compiler versions
+    pub fn storage_name(mut self, storage_name: impl Into<String>) -> Self {
+        self.storage_name = Some(storage_name.into());
+        self
+    }
+
+    /// Only poll jobs with at least this priority, eg. for a pool reserved for urgent work
+    ///
+    /// Defaults to none, polling jobs of any priority
+    pub fn min_priority(mut self, min_priority: i32) -> Self {
+        self.min_priority = Some(min_priority);
+        self
+    }
+
+    /// How many jobs each reclaim of orphaned or failed jobs moves back into the queue
+    ///
+    /// Raise this so a large backlog is not trickled out on a busy queue.
+    ///
+    /// Defaults to the buffer size
+    pub fn heartbeat_batch_size(mut self, batch_size: usize) -> Self {
+        self.heartbeat_batch_size = Some(batch_size);
+        self
+    }
+
+    /// Delete jobs once they are acknowledged instead of marking them as done
+    ///
+    /// Meant for fire-and-forget queues where completed jobs are never looked at again.
+    /// Failed jobs are kept, as are jobs acknowledged with a result or an idempotency key.
+    ///
+    /// Defaults to false
+    pub fn delete_on_ack(mut self, delete_on_ack: bool) -> Self {
+        self.delete_on_ack = delete_on_ack;
+        self
+    }
+
+    /// Randomly lengthen each poll interval by up to this fraction of it
+    ///
+    /// Spreads out the polls of many workers started at the same time,
+    /// eg. `0.5` waits between 1 and 1.5 poll intervals.
+    ///
+    /// Defaults to 0, polling at exactly the poll interval
+    pub fn poll_jitter(mut self, ratio: f64) -> Self {
+        self.poll_jitter = ratio.max(0.0);
+        self
+    }
+
+    /// Offload encoded payloads larger than this many bytes to the storage's payload store
+    ///
+    /// Only a pointer to the payload is kept in the row.
+    ///
+    /// Defaults to None, keeping every payload inline
+    pub fn payload_threshold(mut self, bytes: usize) -> Self {
+        self.payload_threshold = Some(bytes);
+        self
+    }
+
+    /// Refuse to push jobs whose encoded payload is larger than this many bytes
+    ///
+    /// Guards the queue against a producer pushing a huge payload by mistake. Oversized jobs
+    /// fail with an [std::io::ErrorKind::InvalidInput] error naming their size before anything
+    /// is written, including to the payload store.
+    ///
+    /// Defaults to None, accepting payloads of any size
+    pub fn max_payload_bytes(mut self, bytes: usize) -> Self {
+        self.max_payload_bytes = Some(bytes);
+        self
+    }
+
+    /// Log a warning naming the storage operation whenever one of its queries takes longer than this
+    ///
+    /// Defaults to None, not timing queries at all
+    pub fn slow_query_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_query_threshold = Some(threshold);
+        self
+    }
+
+    /// Never run more than this many jobs of the same job type at once, across all workers
+    ///
+    /// The limit is checked by the same statement that locks a job, so concurrent workers
+    /// cannot overshoot it.
+    ///
+    /// Defaults to None, running as many jobs as the workers fetch
+    pub fn max_concurrent(mut self, max_concurrent: usize) -> Self {
+        self.max_concurrent = Some(max_concurrent);
+        self
+    }
+
+    /// Honour the millisecond part of `run_at` for jobs scheduled with millisecond precision
+    ///
+    /// Jobs are otherwise due from the second after their `run_at` second. Only jobs scheduled
+    /// with `SqliteStorage::schedule_at_millis` or `SqliteStorage::schedule_in` while this is on
+    /// carry milliseconds, every other job keeps the second based behaviour.
+    ///
+    /// Defaults to false
+    pub fn millisecond_run_at(mut self, millisecond_run_at: bool) -> Self {
+        self.millisecond_run_at = millisecond_run_at;
+        self
+    }
+
+    /// The minimum time a job that failed waits before it is fetched again
+    ///
+    /// Keeps a job that fails fast from being retried in a tight loop. Jobs that are
+    /// rescheduled explicitly wait as long as they were rescheduled for instead.
+    ///
+    /// Defaults to 0, making failed jobs due again from the next second
+    pub fn failed_cooldown(mut self, cooldown: Duration) -> Self {
+        self.failed_cooldown = cooldown;
+        self
+    }
+
+    /// Remove workers that have not been seen for this long during the heartbeat
+    ///
+    /// Keeps the workers table from growing with the rows of crashed workers whose ids were not
+    /// stable. Workers that still hold running jobs are kept so those jobs can be reclaimed.
+    ///
+    /// Defaults to 1 day
+    pub fn prune_workers_after(mut self, older_than: Duration) -> Self {
+        self.prune_workers_after = older_than;
+        self
+    }
+
+    /// Stop fetching a job type for a while once its jobs keep failing
+    ///
+    /// The state of the breaker is kept in the database, so every worker of the job type
+    /// backs off together.
+    ///
+    /// Defaults to None, fetching jobs regardless of how many recently failed
+    pub fn circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.circuit_breaker = Some(breaker);
+        self
+    }
+
+    /// How many polls in a row have to find no job before `SqliteStorage::consume_until_empty` ends
+    ///
+    /// Polls are the poll interval apart, so a few extra polls give jobs pushed or retried
+    /// while the queue drains a chance to be picked up.
+    ///
+    /// Defaults to 3
+    pub fn stop_after_empty_polls(mut self, polls: usize) -> Self {
+        self.stop_after_empty_polls = polls.max(1);
+        self
+    }
+
+    /// Route jobs with an affinity key to the same worker, eg. to reuse a per-key cache
+    ///
+    /// Each poll first locks the due jobs whose key maps to this worker among the workers of its
+    /// type seen within `live_within`, then any other due job. Keys are mapped by rendezvous
+    /// hashing, so only the keys of a worker that joins or leaves move to another worker.
+    ///
+    /// Defaults to none, where affinity keys are ignored
+    pub fn affinity_routing(mut self, live_within: Duration) -> Self {
+        self.affinity_routing = Some(live_within);
+        self
+    }
+
+    /// What pushing a job with a dedup key does to the pending job with that key
+    ///
+    /// See [apalis_core::storage::Job::dedup_key].
+    ///
+    /// Defaults to [CoalesceMode::KeepEarliest]
+    pub fn coalesce_mode(mut self, mode: CoalesceMode) -> Self {
+        self.coalesce_mode = mode;
+        self
+    }
+
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn reenqueue_batch_size(&self) -> usize {
+        self.heartbeat_batch_size.unwrap_or(self.buffer_size)
+    }
+}
+
+/// Decides which of the eligible jobs wins when a storage polls for work.
+///
+/// Multiple workers of the same type always compete for the same rows,
+/// so the strategy determines which jobs are picked first under load.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum FetchStrategy {
+    /// Oldest inserted jobs first, by `rowid`
+    #[default]
+    Fifo,
+    /// Newest inserted jobs first, by `rowid`
+    ///
+    /// Suits queues where the latest update wins. Older jobs are still consumed once the newer
+    /// ones are done; push them with `SqliteStorage::push_with_id` under one id per key instead
+    /// to replace a stale pending payload rather than queue another job.
+    Lifo,
+    /// Jobs that were due the earliest first, by `run_at`, then by `rowid`
+    RunAtAsc,
+    /// Highest priority jobs first, with the priority rising the longer a job has been due
+    ///
+    /// A job's effective priority is `priority + (now - run_at) / aging_factor`,
+    /// so low priority jobs are not starved forever by a steady stream of urgent ones.
+    /// See [Config::aging_factor].
+    PriorityWithAging,
+}
+
+impl FetchStrategy {
+    /// The `ORDER BY` terms of the strategy, where `?1` is the current time
+    ///
+    /// Every order ends on `rowid` so jobs that tie, eg. pushed in the same second, keep
+    /// their insertion order.
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn order_terms(&self, aging_factor: Duration) -> String {
+        match self {
+            FetchStrategy::Fifo => "rowid ASC".to_string(),
+            FetchStrategy::Lifo => "rowid DESC".to_string(),
+            FetchStrategy::RunAtAsc => "run_at ASC, rowid ASC".to_string(),
+            FetchStrategy::PriorityWithAging => {
+                let aging = aging_factor.as_secs().max(1);
+                format!("priority + (?1 - run_at) / {aging} DESC, rowid ASC")
+            }
+        }
+    }
+}
+
+/// How a pushed job is coalesced into the pending job with the same dedup key
+///
+/// Either way no new job is queued and the push returns the id of the pending job.
+/// Only pending jobs coalesce, so a job pushed while its twin is running or retrying is queued.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CoalesceMode {
+    /// The pending job is left as it is, so it runs when it was first due
+    #[default]
+    KeepEarliest,
+    /// The pending job takes the payload of the pushed job and is due when the pushed job would be,
+    /// so a burst of pushes runs once after the last of them
+    DebounceToLatest,
+}
+
+/// Backs off a job type whose jobs keep failing, eg. while a downstream service is down
+///
+/// Once `failures` jobs of a type failed without one succeeding, each within `window` of the
+/// first, the breaker opens and no job of the type is fetched for `cooldown`. A single job is
+/// then fetched to probe the type: the breaker closes once a job succeeds and opens for another
+/// `cooldown` otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CircuitBreaker {
+    failures: usize,
+    window: Duration,
+    cooldown: Duration,
+}
+
+impl CircuitBreaker {
+    /// Open after `failures` failures within `window`, for `cooldown`
+    pub fn new(failures: usize, window: Duration, cooldown: Duration) -> Self {
+        Self {
+            failures: failures.max(1),
+            window,
+            cooldown,
+        }
+    }
+
+    /// The number of failures that open the breaker
+    pub fn failures(&self) -> usize {
+        self.failures
+    }
+
+    /// The time the failures have to happen within
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// How long the breaker stays open before a job is fetched to probe the job type
+    pub fn cooldown(&self) -> Duration {
+        self.cooldown
+    }
+}
+
+/// A change of a job's status, as recorded in the audit log of job events
+///
+/// Currently only recorded by the sqlite storage, see `SetupOptions::with_job_events`.
+#[derive(Debug, Clone)]
+pub struct JobEvent {
+    job_id: TaskId,
+    from: Option<State>,
+    to: State,
+    worker_id: Option<WorkerId>,
+    at: Timestamp,
+}
+
+impl JobEvent {
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn new(
+        job_id: TaskId,
+        from: Option<State>,
+        to: State,
+        worker_id: Option<WorkerId>,
+        at: Timestamp,
+    ) -> Self {
+        Self {
+            job_id,
+            from,
+            to,
+            worker_id,
+            at,
+        }
+    }
+
+    /// The job whose status changed
+    pub fn job_id(&self) -> &TaskId {
+        &self.job_id
+    }
+
+    /// The status before the change, `None` when the job was pushed
+    pub fn from(&self) -> Option<&State> {
+        self.from.as_ref()
+    }
+
+    /// The status after the change
+    pub fn to(&self) -> &State {
+        &self.to
+    }
+
+    /// The worker that locked, finished or gave up the job, `None` for changes made outside a worker
+    pub fn worker_id(&self) -> Option<&WorkerId> {
+        self.worker_id.as_ref()
+    }
+
+    /// When the status changed, to the second
+    pub fn at(&self) -> Timestamp {
+        self.at
+    }
+}
+
+/// A snapshot of the connection pool used by a storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStatus {
+    size: u32,
+    idle: usize,
+}
+
+impl PoolStatus {
+    #[cfg(any(feature = "sqlite", feature = "postgres", feature = "mysql"))]
+    pub(crate) fn new(size: u32, idle: usize) -> Self {
+        Self { size, idle }
+    }
+
+    /// The number of connections currently held by the pool, both active and idle
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// The number of idle connections
+    pub fn idle(&self) -> usize {
+        self.idle
+    }
+}
+
+/// The 50th, 95th and 99th percentile of a duration, at second resolution
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Percentiles {
+    p50: Duration,
+    p95: Duration,
+    p99: Duration,
+}
+
+impl Percentiles {
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn new(p50: Duration, p95: Duration, p99: Duration) -> Self {
+        Self { p50, p95, p99 }
+    }
+
+    /// The median
+    pub fn p50(&self) -> Duration {
+        self.p50
+    }
+
+    /// The 95th percentile
+    pub fn p95(&self) -> Duration {
+        self.p95
+    }
+
+    /// The 99th percentile
+    pub fn p99(&self) -> Duration {
+        self.p99
+    }
+}
+
+/// The latency of recently completed jobs, eg for SLO dashboards
+///
+/// Every percentile is zero if no job completed in the window.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencyStats {
+    samples: u64,
+    queued: Percentiles,
+    running: Percentiles,
+}
+
+impl LatencyStats {
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn new(samples: u64, queued: Percentiles, running: Percentiles) -> Self {
+        Self {
+            samples,
+            queued,
+            running,
+        }
+    }
+
+    /// The number of completed jobs the percentiles are computed from
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    /// How long jobs waited from being due until a worker started them
+    pub fn queued(&self) -> Percentiles {
+        self.queued
+    }
+
+    /// How long jobs ran from being started until they were done
+    pub fn running(&self) -> Percentiles {
+        self.running
+    }
+}
+
+/// A filtered, sorted and paginated listing of jobs, eg for an admin dashboard, see `SqliteStorage::query_jobs`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobQuery {
+    statuses: Vec<State>,
+    job_type: Option<String>,
+    run_at_between: Option<(DateTime<Utc>, DateTime<Utc>)>,
+    search: Option<String>,
+    order: JobOrder,
+    page: u32,
+    per_page: u32,
+}
+
+impl Default for JobQuery {
+    fn default() -> Self {
+        Self {
+            statuses: Vec::new(),
+            job_type: None,
+            run_at_between: None,
+            search: None,
+            order: JobOrder::default(),
+            page: 1,
+            per_page: 10,
+        }
+    }
+}
+
+impl JobQuery {
+    /// Only match jobs in this state
+    ///
+    /// Can be called more than once to match jobs in any of the states. Defaults to every state
+    pub fn status(mut self, status: State) -> Self {
+        self.statuses.push(status);
+        self
+    }
+
+    /// Only match jobs stored under this name, eg one variant of a job enum
+    pub fn job_type(mut self, job_type: impl Into<String>) -> Self {
+        self.job_type = Some(job_type.into());
+        self
+    }
+
+    /// Only match jobs with a `run_at` from `from` up to but excluding `to`
+    ///
+    /// Jobs pushed without a schedule run at the time they are pushed.
+    pub fn run_at_between(mut self, from: DateTime<Utc>, to: DateTime<Utc>) -> Self {
+        self.run_at_between = Some((from, to));
+        self
+    }
+
+    /// Only match jobs whose stored payload contains the text, ignoring ASCII case
+    ///
+    /// Offloaded payloads are not searched, see [Config::payload_threshold].
+    pub fn search(mut self, text: impl Into<String>) -> Self {
+        self.search = Some(text.into());
+        self
+    }
+
+    /// The order of the matched jobs
+    ///
+    /// Defaults to [JobOrder::RunAtAsc]
+    pub fn order_by(mut self, order: JobOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// The page of matched jobs to return, starting from 1
+    ///
+    /// Defaults to 1
+    pub fn page(mut self, page: u32) -> Self {
+        self.page = page;
+        self
+    }
+
+    /// The number of jobs per page
+    ///
+    /// Defaults to 10
+    pub fn per_page(mut self, per_page: u32) -> Self {
+        self.per_page = per_page;
+        self
+    }
+}
+
+/// The order of the jobs matched by a [JobQuery]
+///
+/// Jobs that compare equal are kept in insertion order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum JobOrder {
+    /// Jobs due the earliest first
+    #[default]
+    RunAtAsc,
+    /// Jobs due the latest first
+    RunAtDesc,
+    /// The most recently finished jobs first, followed by unfinished jobs
+    DoneAtDesc,
+    /// Highest priority jobs first
+    PriorityDesc,
+}
+
+impl JobOrder {
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn order_terms(&self) -> &'static str {
+        match self {
+            JobOrder::RunAtAsc => "run_at ASC, rowid ASC",
+            JobOrder::RunAtDesc => "run_at DESC, rowid ASC",
+            JobOrder::DoneAtDesc => "done_at DESC, rowid ASC",
+            JobOrder::PriorityDesc => "priority DESC, rowid ASC",
+        }
+    }
+}
+
+/// An error a job handler can return to decide whether its job is retried
+///
+/// Honoured by `SqliteStorage::serve`. Errors of any other type are retried,
+/// except for [apalis_core::error::Error::Abort].
+#[derive(Debug)]
+pub enum JobError {
+    /// A transient failure, the job is retried
+    Retryable(BoxDynError),
+    /// A transient failure that knows when to retry, eg. from a `Retry-After` header.
+    /// The job is retried after the delay instead of the one the worker was configured with
+    RetryAfter(BoxDynError, Duration),
+    /// A permanent failure, eg. invalid input, the job is killed without using up its attempts
+    Abort(BoxDynError),
+}
+
+impl JobError {
+    /// Build a [JobError::Retryable] from any error
+    pub fn retryable(error: impl Into<BoxDynError>) -> Self {
+        JobError::Retryable(error.into())
+    }
+
+    /// Build a [JobError::RetryAfter] from any error
+    pub fn retry_after(error: impl Into<BoxDynError>, delay: Duration) -> Self {
+        JobError::RetryAfter(error.into(), delay)
+    }
+
+    /// Build a [JobError::Abort] from any error
+    pub fn abort(error: impl Into<BoxDynError>) -> Self {
+        JobError::Abort(error.into())
+    }
+
+    /// The message of an error returned by a handler that should kill its job instead of retrying it
+    ///
+    /// The error's sources are checked too, as handler errors are usually wrapped on the way out.
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn abort_message(error: &(dyn std::error::Error + 'static)) -> Option<String> {
+        let mut error = Some(error);
+        while let Some(e) = error {
+            match (
+                e.downcast_ref::<JobError>(),
+                e.downcast_ref::<apalis_core::error::Error>(),
+            ) {
+                (Some(JobError::Abort(_)), _) | (_, Some(apalis_core::error::Error::Abort)) => {
+                    return Some(e.to_string())
+                }
+                // The first decision found wins
+                (Some(JobError::Retryable(_) | JobError::RetryAfter(..)), _) => return None,
+                _ => error = e.source(),
+            }
+        }
+        None
+    }
+
+    /// The delay an error returned by a handler asks its job to be retried after, if any
+    ///
+    /// Like [JobError::abort_message] the error's sources are checked too.
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn retry_hint(error: &(dyn std::error::Error + 'static)) -> Option<Duration> {
+        let mut error = Some(error);
+        while let Some(e) = error {
+            match e.downcast_ref::<JobError>() {
+                Some(JobError::RetryAfter(_, delay)) => return Some(*delay),
+                Some(_) => return None,
+                None => error = e.source(),
+            }
+        }
+        None
+    }
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::Retryable(e) | JobError::RetryAfter(e, _) | JobError::Abort(e) => {
+                write!(f, "{e}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for JobError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            JobError::Retryable(e) | JobError::RetryAfter(e, _) | JobError::Abort(e) => {
+                Some(e.as_ref())
+            }
+        }
+    }
 }
@@ -25,7 +25,7 @@ use std::{marker::PhantomData, ops::Add, time::Duration};
 
 use crate::context::SqlContext;
 use crate::from_row::SqlRequest;
-use crate::Config;
+use crate::{Config, PoolStatus};
 
 pub use sqlx::mysql::MySqlPool;
 
@@ -107,6 +107,11 @@ impl<T: Serialize + DeserializeOwned> MysqlStorage<T> {
     pub fn pool(&self) -> &Pool<MySql> {
         &self.pool
     }
+
+    /// Get the current size and idle count of the connection pool
+    pub fn pool_status(&self) -> PoolStatus {
+        PoolStatus::new(self.pool.size(), self.pool.num_idle())
+    }
 }
 
 impl<T: DeserializeOwned + Send + Unpin + Job + Sync + 'static> MysqlStorage<T> {
@@ -203,7 +208,7 @@ where
     async fn push(&mut self, job: Self::Job) -> Result<TaskId, sqlx::Error> {
         let id = TaskId::new();
         let query =
-            "INSERT INTO jobs VALUES (?, ?, ?, 'Pending', 0, 25, now(), NULL, NULL, NULL, NULL)";
+            "INSERT INTO jobs (job, id, job_type, status, attempts, max_attempts, run_at) VALUES (?, ?, ?, 'Pending', 0, 25, now())";
         let pool = self.pool.clone();
 
         let job = self
@@ -222,7 +227,7 @@ where
 
     async fn schedule(&mut self, job: Self::Job, on: i64) -> Result<TaskId, sqlx::Error> {
         let query =
-            "INSERT INTO jobs VALUES (?, ?, ?, 'Pending', 0, 25, ?, NULL, NULL, NULL, NULL)";
+            "INSERT INTO jobs (job, id, job_type, status, attempts, max_attempts, run_at) VALUES (?, ?, ?, 'Pending', 0, 25, ?)";
         let pool = self.pool.clone();
         let id = TaskId::new();
 
@@ -308,9 +313,13 @@ where
             )))?;
         let status = ctx.status().to_string();
         let attempts = ctx.attempts();
-        let done_at = *ctx.done_at();
+        let done_at = ctx
+            .done_at()
+            .and_then(|t| DateTime::from_timestamp_millis(t.as_millis()));
         let lock_by = ctx.lock_by().clone();
-        let lock_at = *ctx.lock_at();
+        let lock_at = ctx
+            .lock_at()
+            .and_then(|t| DateTime::from_timestamp_millis(t.as_millis()));
         let last_error = ctx.last_error().clone();
         let job_id = ctx.id();
         let mut tx = pool.acquire().await?;
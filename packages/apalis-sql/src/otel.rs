@@ -0,0 +1,79 @@
+use std::time::Duration;
+
+use opentelemetry::metrics::{Counter, Gauge, Histogram, Meter};
+use opentelemetry::KeyValue;
+
+/// The OpenTelemetry instruments a storage records its jobs with
+///
+/// Instruments are named after the semantic conventions for messaging, with the job type
+/// as the destination. Those without a convention are prefixed with `apalis`.
+#[derive(Debug, Clone)]
+pub(crate) struct Instruments {
+    sent: Counter<u64>,
+    consumed: Counter<u64>,
+    process_duration: Histogram<f64>,
+    failed: Counter<u64>,
+    queue_depth: Gauge<u64>,
+}
+
+impl Instruments {
+    pub(crate) fn new(meter: &Meter) -> Self {
+        Self {
+            sent: meter
+                .u64_counter("messaging.client.sent.messages")
+                .with_description("Number of jobs pushed")
+                .with_unit("{message}")
+                .init(),
+            consumed: meter
+                .u64_counter("messaging.client.consumed.messages")
+                .with_description("Number of jobs fetched by workers")
+                .with_unit("{message}")
+                .init(),
+            process_duration: meter
+                .f64_histogram("messaging.process.duration")
+                .with_description("Time from a worker locking a job until it was acknowledged")
+                .with_unit("s")
+                .init(),
+            failed: meter
+                .u64_counter("apalis.jobs.failed")
+                .with_description("Number of failed attempts of jobs")
+                .with_unit("{message}")
+                .init(),
+            queue_depth: meter
+                .u64_gauge("apalis.queue.depth")
+                .with_description("Number of pending jobs")
+                .with_unit("{message}")
+                .init(),
+        }
+    }
+
+    pub(crate) fn sent(&self, job_type: &str) {
+        self.sent.add(1, &attributes(job_type, "send"));
+    }
+
+    pub(crate) fn consumed(&self, job_type: &str) {
+        self.consumed.add(1, &attributes(job_type, "receive"));
+    }
+
+    pub(crate) fn processed(&self, job_type: &str, duration: Duration) {
+        self.process_duration
+            .record(duration.as_secs_f64(), &attributes(job_type, "process"));
+    }
+
+    pub(crate) fn failed(&self, job_type: &str) {
+        self.failed.add(1, &attributes(job_type, "process"));
+    }
+
+    pub(crate) fn queue_depth(&self, job_type: &str, depth: u64) {
+        self.queue_depth
+            .record(depth, &attributes(job_type, "receive"));
+    }
+}
+
+fn attributes(job_type: &str, operation: &'static str) -> [KeyValue; 3] {
+    [
+        KeyValue::new("messaging.system", "apalis"),
+        KeyValue::new("messaging.destination.name", job_type.to_string()),
+        KeyValue::new("messaging.operation.name", operation),
+    ]
+}
@@ -0,0 +1,100 @@
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+#[cfg(feature = "sqlite")]
+/// Prefix of the pointer stored in place of an offloaded payload
+pub(crate) const POINTER_PREFIX: &[u8] = b"apalis-payload:";
+
+/// An external store for job payloads that are too large to keep inline
+///
+/// Payloads are written once when a job is pushed and read back every time the job is fetched.
+/// Offloaded payloads are not removed when a job is deleted.
+pub trait PayloadStore {
+    /// Store a payload under the given key, replacing any previous payload
+    fn put(&self, key: &str, payload: &[u8]) -> io::Result<()>;
+
+    /// Load the payload stored under the given key
+    fn get(&self, key: &str) -> io::Result<Vec<u8>>;
+}
+
+/// A [PayloadStore] that keeps every payload in its own file in a directory
+#[derive(Clone)]
+pub struct FsPayloadStore {
+    dir: PathBuf,
+}
+
+impl FsPayloadStore {
+    /// Store payloads in the given directory, which is created on the first write if missing
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// The directory payloads are stored in
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid payload key {key:?}"),
+            ));
+        }
+        Ok(self.dir.join(key))
+    }
+}
+
+impl Default for FsPayloadStore {
+    /// Stores payloads in an `apalis-payloads` directory under the system temp dir
+    fn default() -> Self {
+        Self::new(std::env::temp_dir().join("apalis-payloads"))
+    }
+}
+
+impl fmt::Debug for FsPayloadStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FsPayloadStore")
+            .field("dir", &self.dir)
+            .finish()
+    }
+}
+
+impl PayloadStore for FsPayloadStore {
+    fn put(&self, key: &str, payload: &[u8]) -> io::Result<()> {
+        let path = self.path(key)?;
+        fs::create_dir_all(&self.dir)?;
+        // Write to a temporary file first so a reader never sees a partial payload
+        let tmp = self.dir.join(format!(".{key}.tmp"));
+        fs::write(&tmp, payload)?;
+        fs::rename(tmp, path)
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.path(key)?)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+/// Build the pointer stored in the row in place of an offloaded payload
+pub(crate) fn pointer(key: &str) -> Vec<u8> {
+    [POINTER_PREFIX, key.as_bytes()].concat()
+}
+
+#[cfg(feature = "sqlite")]
+/// Resolve a stored payload, loading it from the store if it was offloaded
+pub(crate) fn resolve(
+    store: &(dyn PayloadStore + Send + Sync),
+    stored: Vec<u8>,
+) -> io::Result<Vec<u8>> {
+    match stored.strip_prefix(POINTER_PREFIX) {
+        Some(key) => {
+            let key = std::str::from_utf8(key)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            store.get(key)
+        }
+        None => Ok(stored),
+    }
+}
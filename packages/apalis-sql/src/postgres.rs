@@ -39,7 +39,7 @@
 //!  }
 //! ```
 use crate::context::SqlContext;
-use crate::Config;
+use crate::{Config, PoolStatus};
 use apalis_core::codec::json::JsonCodec;
 use apalis_core::error::Error;
 use apalis_core::layers::{Ack, AckLayer};
@@ -87,6 +87,7 @@ pub struct PostgresStorage<T> {
     config: Config,
     controller: Controller,
     ack_notify: Notify<(WorkerId, TaskId)>,
+    subscription: Option<PgSubscription>,
 }
 
 impl<T> Clone for PostgresStorage<T> {
@@ -98,6 +99,7 @@ impl<T> Clone for PostgresStorage<T> {
             config: self.config.clone(),
             controller: self.controller.clone(),
             ack_notify: self.ack_notify.clone(),
+            subscription: self.subscription.clone(),
         }
     }
 }
@@ -114,6 +116,7 @@ impl<T> fmt::Debug for PostgresStorage<T> {
                 &"Arc<Box<dyn Codec<T, serde_json::Value, Error = Error> + Sync + Send + 'static>>",
             )
             .field("ack_notify", &self.ack_notify)
+            .field("subscription", &self.subscription)
             .finish()
     }
 }
@@ -206,13 +209,31 @@ impl<T: Serialize + DeserializeOwned> PostgresStorage<T> {
             config,
             controller: Controller::new(),
             ack_notify: Notify::new(),
+            subscription: None,
         }
     }
 
+    /// Wake up polling as soon as a job of this type is inserted.
+    ///
+    /// Interval polling keeps running as a safety net for missed notifications.
+    /// Remember to spawn [PgListen::listen] once all storages are subscribed.
+    pub fn with_notify(mut self, listener: &mut PgListen) -> Self
+    where
+        T: Job,
+    {
+        self.subscription = Some(listener.subscribe::<T>());
+        self
+    }
+
     /// Expose the pool for other functionality, eg custom migrations
     pub fn pool(&self) -> &Pool<Postgres> {
         &self.pool
     }
+
+    /// Get the current size and idle count of the connection pool
+    pub fn pool_status(&self) -> PoolStatus {
+        PoolStatus::new(self.pool.size(), self.pool.num_idle())
+    }
 }
 
 /// A listener that listens to Postgres notifications
@@ -274,10 +295,18 @@ impl<T: DeserializeOwned + Send + Unpin + Job + 'static> PostgresStorage<T> {
         let pool = self.pool.clone();
         let worker_id = worker_id.clone();
         let codec = self.codec.clone();
+        let subscription = self.subscription.clone();
         try_stream! {
             loop {
                 //  Ideally wait for a job or a tick
-                apalis_core::sleep(interval).await;
+                match &subscription {
+                    Some(sub) => {
+                        let tick = apalis_core::sleep(interval).boxed();
+                        let notified = sub.notify.notified().boxed();
+                        futures::future::select(tick, notified).await;
+                    }
+                    None => apalis_core::sleep(interval).await,
+                }
                 let tx = pool.clone();
                 let job_type = T::NAME;
                 let fetch_query = "Select * from apalis.get_jobs($1, $2, $3);";
@@ -347,7 +376,7 @@ where
     /// ```
     async fn push(&mut self, job: Self::Job) -> Result<TaskId, sqlx::Error> {
         let id = TaskId::new();
-        let query = "INSERT INTO apalis.jobs VALUES ($1, $2, $3, 'Pending', 0, 25, NOW() , NULL, NULL, NULL, NULL)";
+        let query = "INSERT INTO apalis.jobs (job, id, job_type, status, attempts, max_attempts, run_at) VALUES ($1, $2, $3, 'Pending', 0, 25, NOW())";
         let pool = self.pool.clone();
         let job = self
             .codec
@@ -365,7 +394,7 @@ where
 
     async fn schedule(&mut self, job: Self::Job, on: Timestamp) -> Result<TaskId, sqlx::Error> {
         let query =
-            "INSERT INTO apalis.jobs VALUES ($1, $2, $3, 'Pending', 0, 25, $4, NULL, NULL, NULL, NULL)";
+            "INSERT INTO apalis.jobs (job, id, job_type, status, attempts, max_attempts, run_at) VALUES ($1, $2, $3, 'Pending', 0, 25, $4)";
         let pool = self.pool.clone();
         let id = TaskId::new();
         let on = DateTime::from_timestamp(on, 0);
@@ -453,9 +482,13 @@ where
             .current()
             .try_into()
             .map_err(|e| sqlx::Error::Io(io::Error::new(io::ErrorKind::InvalidData, e)))?;
-        let done_at = *ctx.done_at();
+        let done_at = ctx
+            .done_at()
+            .and_then(|t| DateTime::from_timestamp_millis(t.as_millis()));
         let lock_by = ctx.lock_by().clone();
-        let lock_at = *ctx.lock_at();
+        let lock_at = ctx
+            .lock_at()
+            .and_then(|t| DateTime::from_timestamp_millis(t.as_millis()));
         let last_error = ctx.last_error().clone();
 
         let mut tx = pool.acquire().await?;
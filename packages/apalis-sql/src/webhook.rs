@@ -0,0 +1,64 @@
+use std::time::Duration;
+
+use log::{error, warn};
+use serde::Serialize;
+
+use crate::context::State;
+
+/// How many times a callback is posted before it is given up on
+const DELIVERY_ATTEMPTS: u32 = 5;
+/// The wait before the first retry, doubled after every failed delivery
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+/// How long a callback url has to answer a delivery
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The body posted to the callback url of a job once it finished
+#[derive(Debug, Serialize)]
+pub(crate) struct Callback {
+    pub(crate) job_id: String,
+    pub(crate) status: State,
+    pub(crate) result: Option<serde_json::Value>,
+}
+
+/// Post `callback` to `url` in the background, retrying with a backoff
+///
+/// Deliveries never fail the job they report on, failures are only logged.
+pub(crate) fn deliver(url: String, callback: Callback) {
+    let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+        warn!(
+            "Dropping the callback of job {} to {url}: not running on a tokio runtime",
+            callback.job_id
+        );
+        return;
+    };
+    runtime.spawn(async move {
+        let client = reqwest::Client::builder()
+            .timeout(DELIVERY_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        let mut backoff = RETRY_BACKOFF;
+        for attempt in 1..=DELIVERY_ATTEMPTS {
+            let sent = client
+                .post(&url)
+                .json(&callback)
+                .send()
+                .await
+                .and_then(|res| res.error_for_status());
+            match sent {
+                Ok(_) => return,
+                Err(e) if attempt < DELIVERY_ATTEMPTS => {
+                    warn!(
+                        "Callback of job {} to {url} failed on attempt {attempt}, retrying: {e}",
+                        callback.job_id
+                    );
+                    apalis_core::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(e) => error!(
+                    "Giving up on the callback of job {} to {url} after {attempt} attempts: {e}",
+                    callback.job_id
+                ),
+            }
+        }
+    });
+}
@@ -136,13 +136,15 @@ pub mod utils {
 pub mod prelude {
     #[cfg(feature = "tokio-comp")]
     pub use crate::utils::TokioExecutor;
+    #[allow(deprecated)]
+    pub use apalis_core::memory::MemoryWrapper;
     pub use apalis_core::{
         builder::{WorkerBuilder, WorkerFactory, WorkerFactoryFn},
         data::Extensions,
         error::{BoxDynError, Error},
         executor::Executor,
         layers::extensions::{AddExtension, Data},
-        memory::{MemoryStorage, MemoryWrapper},
+        memory::MemoryStorage,
         monitor::{Monitor, MonitorContext},
         mq::{Message, MessageQueue},
         notify::Notify,